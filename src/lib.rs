@@ -1,13 +1,182 @@
 use nih_plug::prelude::*;
 use std::sync::Arc;
 
+#[derive(Clone, Copy, PartialEq)]
+enum AdsrStage {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+    Idle,
+}
+
+// Unlike a Smoother, tracks its own stage so a note-off can retrigger a
+// release ramp from wherever the envelope currently is.
+struct Adsr {
+    stage: AdsrStage,
+    level: f32,
+    peak: f32,
+    sustain_level: f32,
+    attack_increment: f32,
+    decay_increment: f32,
+    release_increment: f32,
+}
+
+impl Adsr {
+    fn new() -> Self {
+        Self {
+            stage: AdsrStage::Idle,
+            level: 0.0,
+            peak: 1.0,
+            sustain_level: 0.0,
+            attack_increment: 1.0,
+            decay_increment: 1.0,
+            release_increment: 1.0,
+        }
+    }
+
+    fn note_on(&mut self, velocity: f32, sample_rate: f32, attack_secs: f32) {
+        self.stage = AdsrStage::Attack;
+        self.peak = velocity;
+        self.level = 0.0;
+        self.attack_increment = 1.0 / (attack_secs * sample_rate).max(1.0);
+    }
+
+    fn note_off(&mut self, sample_rate: f32, release_secs: f32) {
+        if self.stage != AdsrStage::Idle {
+            self.stage = AdsrStage::Release;
+            self.release_increment = self.level / (release_secs * sample_rate).max(1.0);
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.stage == AdsrStage::Idle
+    }
+
+    fn next(&mut self, sample_rate: f32, decay_secs: f32, sustain: f32) -> f32 {
+        self.decay_increment = (self.peak - self.peak * sustain) / (decay_secs * sample_rate).max(1.0);
+        self.sustain_level = self.peak * sustain;
+
+        match self.stage {
+            AdsrStage::Attack => {
+                self.level += self.attack_increment * self.peak;
+                if self.level >= self.peak {
+                    self.level = self.peak;
+                    self.stage = AdsrStage::Decay;
+                }
+            }
+            AdsrStage::Decay => {
+                self.level -= self.decay_increment;
+                if self.level <= self.sustain_level {
+                    self.level = self.sustain_level;
+                    self.stage = AdsrStage::Sustain;
+                }
+            }
+            AdsrStage::Sustain => {
+                self.level = self.sustain_level;
+            }
+            AdsrStage::Release => {
+                self.level -= self.release_increment;
+                if self.level <= 0.0 {
+                    self.level = 0.0;
+                    self.stage = AdsrStage::Idle;
+                }
+            }
+            AdsrStage::Idle => {
+                self.level = 0.0;
+            }
+        }
+
+        self.level
+    }
+}
+
+// Fixed voice count / steal-quietest strategy; not exposed as a param.
+const NUM_VOICES: usize = 16;
+
+struct Voice {
+    midi_note_id: u8,
+    midi_note_freq: f32,
+    velocity: f32,
+    phase: f32,
+    envelope: Adsr,
+    // Leaky-integrates the square wave into a triangle wave.
+    triangle_integrator: f32,
+    // xorshift32 state for the noise generator.
+    noise_state: u32,
+}
+
+impl Voice {
+    fn new(seed: u32) -> Self {
+        Self {
+            midi_note_id: 0,
+            midi_note_freq: 1.0,
+            velocity: 0.0,
+            phase: 0.0,
+            envelope: Adsr::new(),
+            triangle_integrator: 0.0,
+            noise_state: seed,
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.envelope.is_idle()
+    }
+
+    fn note_on(&mut self, note: u8, velocity: f32, sample_rate: f32, attack_secs: f32) {
+        self.midi_note_id = note;
+        self.midi_note_freq = util::midi_note_to_freq(note);
+        self.velocity = velocity;
+        self.phase = 0.0;
+        self.triangle_integrator = 0.0;
+        self.envelope.note_on(velocity, sample_rate, attack_secs);
+    }
+}
+
 struct Osc {
     params: Arc<OscParams>,
     sample_rate: f32,
-    phase: f32,
-    midi_note_id: u8,
-    midi_note_freq: f32,
-    midi_note_gain: Smoother<f32>,
+    voices: Vec<Voice>,
+    // Free-running LFO phase, used when `lfo_sync` is off.
+    lfo_phase: f32,
+    // One ring buffer per output channel for the feedback delay.
+    delay_buffers: Vec<Vec<f32>>,
+    delay_write_index: usize,
+}
+
+const MAX_DELAY_SECS: f32 = 1.0;
+
+#[derive(Enum, PartialEq)]
+enum LfoDestination {
+    #[name = "Pitch"]
+    #[id = "pitch"]
+    Pitch,
+    #[name = "Amplitude"]
+    #[id = "amplitude"]
+    Amplitude,
+}
+
+#[derive(Enum, PartialEq)]
+enum LfoDivision {
+    #[name = "1/4"]
+    #[id = "1_4"]
+    Quarter,
+    #[name = "1/8"]
+    #[id = "1_8"]
+    Eighth,
+    #[name = "1/16"]
+    #[id = "1_16"]
+    Sixteenth,
+}
+
+impl LfoDivision {
+    fn beats(&self) -> f64 {
+        match self {
+            LfoDivision::Quarter => 1.0,
+            LfoDivision::Eighth => 0.5,
+            LfoDivision::Sixteenth => 0.25,
+        }
+    }
 }
 
 #[derive(Enum, PartialEq)]
@@ -15,6 +184,32 @@ enum OscMode {
     #[name = "Sine Wave"]
     #[id = "sine"]
     Sine,
+    #[name = "Saw Wave"]
+    #[id = "saw"]
+    Saw,
+    #[name = "Square Wave"]
+    #[id = "square"]
+    Square,
+    #[name = "Triangle Wave"]
+    #[id = "triangle"]
+    Triangle,
+    #[name = "Noise"]
+    #[id = "noise"]
+    Noise,
+}
+
+// PolyBLEP correction to de-alias the naive saw/square phasor. `t` is phase
+// (`0..1`), `dt` is the phase increment for one sample.
+fn poly_blep(t: f32, dt: f32) -> f32 {
+    if t < dt {
+        let t = t / dt;
+        t + t - t * t - 1.0
+    } else if t > 1.0 - dt {
+        let t = (t - 1.0) / dt;
+        t * t + t + t + 1.0
+    } else {
+        0.0
+    }
 }
 
 #[derive(Params)]
@@ -27,6 +222,45 @@ struct OscParams {
 
     #[id = "mode"]
     pub mode: EnumParam<OscMode>,
+
+    #[id = "attack"]
+    pub attack: FloatParam,
+
+    #[id = "decay"]
+    pub decay: FloatParam,
+
+    #[id = "sustain"]
+    pub sustain: FloatParam,
+
+    #[id = "release"]
+    pub release: FloatParam,
+
+    #[id = "lfo_rate"]
+    pub lfo_rate: FloatParam,
+
+    #[id = "lfo_sync"]
+    pub lfo_sync: BoolParam,
+
+    #[id = "lfo_division"]
+    pub lfo_division: EnumParam<LfoDivision>,
+
+    #[id = "lfo_depth"]
+    pub lfo_depth: FloatParam,
+
+    #[id = "lfo_destination"]
+    pub lfo_destination: EnumParam<LfoDestination>,
+
+    #[id = "pd"]
+    pub pd: FloatParam,
+
+    #[id = "delay_time"]
+    pub delay_time: FloatParam,
+
+    #[id = "delay_feedback"]
+    pub delay_feedback: FloatParam,
+
+    #[id = "delay_mix"]
+    pub delay_mix: FloatParam,
 }
 
 impl Default for Osc {
@@ -34,10 +268,12 @@ impl Default for Osc {
         Self {
             params: Arc::new(OscParams::default()),
             sample_rate: 1.0, // TODO ???
-            phase: 0.0,
-            midi_note_id: 0,
-            midi_note_freq: 1.0,
-            midi_note_gain: Smoother::new(SmoothingStyle::Linear(5.0)),
+            voices: (0..NUM_VOICES as u32)
+                .map(|i| Voice::new(0x1234_5678 ^ i.wrapping_mul(0x9e37_79b9)))
+                .collect(),
+            lfo_phase: 0.0,
+            delay_buffers: Vec::new(),
+            delay_write_index: 0,
         }
     }
 }
@@ -71,22 +307,245 @@ impl Default for OscParams {
 
         let mode = EnumParam::new("Mode", OscMode::Sine);
 
-        Self { gain, freq, mode }
+        let attack = FloatParam::new(
+            "Attack",
+            5.0,
+            FloatRange::Skewed {
+                min: 1.0,
+                max: 10_000.0,
+                factor: FloatRange::skew_factor(-2.0),
+            },
+        )
+        .with_step_size(0.1)
+        .with_unit(" ms");
+
+        let decay = FloatParam::new(
+            "Decay",
+            50.0,
+            FloatRange::Skewed {
+                min: 1.0,
+                max: 10_000.0,
+                factor: FloatRange::skew_factor(-2.0),
+            },
+        )
+        .with_step_size(0.1)
+        .with_unit(" ms");
+
+        let sustain = FloatParam::new("Sustain", 1.0, FloatRange::Linear { min: 0.0, max: 1.0 })
+            .with_step_size(0.01);
+
+        let release = FloatParam::new(
+            "Release",
+            100.0,
+            FloatRange::Skewed {
+                min: 1.0,
+                max: 10_000.0,
+                factor: FloatRange::skew_factor(-2.0),
+            },
+        )
+        .with_step_size(0.1)
+        .with_unit(" ms");
+
+        let lfo_rate = FloatParam::new(
+            "LFO Rate",
+            2.0,
+            FloatRange::Skewed {
+                min: 0.01,
+                max: 20.0,
+                factor: FloatRange::skew_factor(-1.0),
+            },
+        )
+        .with_unit(" Hz");
+
+        let lfo_sync = BoolParam::new("LFO Sync", false);
+
+        let lfo_division = EnumParam::new("LFO Division", LfoDivision::Eighth);
+
+        let lfo_depth = FloatParam::new("LFO Depth", 0.0, FloatRange::Linear { min: 0.0, max: 1.0 })
+            .with_smoother(SmoothingStyle::Linear(10.0))
+            .with_step_size(0.01)
+            .with_unit(" %")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage());
+
+        let lfo_destination = EnumParam::new("LFO Destination", LfoDestination::Pitch);
+
+        let pd = FloatParam::new(
+            "Phase Distortion",
+            0.5,
+            FloatRange::Linear {
+                min: 0.05,
+                max: 0.95,
+            },
+        )
+        .with_smoother(SmoothingStyle::Linear(10.0))
+        .with_step_size(0.01);
+
+        let delay_time = FloatParam::new(
+            "Delay Time",
+            0.3,
+            FloatRange::Skewed {
+                min: 0.01,
+                max: MAX_DELAY_SECS,
+                factor: FloatRange::skew_factor(-1.0),
+            },
+        )
+        .with_unit(" s");
+
+        let delay_feedback = FloatParam::new(
+            "Delay Feedback",
+            0.3,
+            FloatRange::Linear { min: 0.0, max: 0.95 },
+        )
+        .with_smoother(SmoothingStyle::Linear(10.0))
+        .with_step_size(0.01);
+
+        let delay_mix = FloatParam::new("Delay Mix", 0.0, FloatRange::Linear { min: 0.0, max: 1.0 })
+            .with_smoother(SmoothingStyle::Linear(10.0))
+            .with_step_size(0.01);
+
+        Self {
+            gain,
+            freq,
+            mode,
+            attack,
+            decay,
+            sustain,
+            release,
+            lfo_rate,
+            lfo_sync,
+            lfo_division,
+            lfo_depth,
+            lfo_destination,
+            pd,
+            delay_time,
+            delay_feedback,
+            delay_mix,
+        }
     }
 }
 
-impl Osc {
-    fn calculate_sine(&mut self, frequency: f32) -> f32 {
-        let phase_delta = frequency / self.sample_rate;
-        let sine = (self.phase * std::f32::consts::TAU).sin();
+impl Voice {
+    fn calculate_sample(&mut self, mode: OscMode, sample_rate: f32, frequency: f32, pd: f32) -> f32 {
+        match mode {
+            OscMode::Sine => self.calculate_sine(sample_rate, frequency, pd),
+            OscMode::Saw => self.calculate_saw(sample_rate, frequency),
+            OscMode::Square => self.calculate_square(sample_rate, frequency),
+            OscMode::Triangle => self.calculate_triangle(sample_rate, frequency),
+            OscMode::Noise => self.calculate_noise(),
+        }
+    }
 
-        self.phase += phase_delta;
+    fn advance_phase(&mut self, dt: f32) {
+        self.phase += dt;
         if self.phase >= 1.0 {
             self.phase -= 1.0;
         }
+    }
 
+    // Warps the phase before the sine lookup (classic Casio CZ trick).
+    fn calculate_sine(&mut self, sample_rate: f32, frequency: f32, pd: f32) -> f32 {
+        let dt = frequency / sample_rate;
+
+        let warped_phase = if self.phase < pd {
+            self.phase / pd * 0.5
+        } else {
+            (1.0 + (self.phase - pd) / (1.0 - pd)) * 0.5
+        };
+        let sine = (warped_phase * std::f32::consts::TAU).sin();
+
+        self.advance_phase(dt);
         sine
     }
+
+    fn calculate_saw(&mut self, sample_rate: f32, frequency: f32) -> f32 {
+        let dt = frequency / sample_rate;
+        let saw = 2.0 * self.phase - 1.0 - poly_blep(self.phase, dt);
+        self.advance_phase(dt);
+        saw
+    }
+
+    fn calculate_square(&mut self, sample_rate: f32, frequency: f32) -> f32 {
+        let dt = frequency / sample_rate;
+        let mut square = if self.phase < 0.5 { 1.0 } else { -1.0 };
+        square += poly_blep(self.phase, dt);
+        let falling_phase = (self.phase + 0.5) % 1.0;
+        square -= poly_blep(falling_phase, dt);
+        self.advance_phase(dt);
+        square
+    }
+
+    fn calculate_triangle(&mut self, sample_rate: f32, frequency: f32) -> f32 {
+        let dt = frequency / sample_rate;
+        let square = self.calculate_square(sample_rate, frequency);
+        // Leaky-integrate the band-limited square wave into a triangle wave.
+        self.triangle_integrator = dt * square + (1.0 - dt) * self.triangle_integrator;
+        self.triangle_integrator * 4.0
+    }
+
+    fn calculate_noise(&mut self) -> f32 {
+        // xorshift32
+        let mut x = self.noise_state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.noise_state = x;
+
+        (x as f32 / u32::MAX as f32) * 2.0 - 1.0
+    }
+}
+
+impl Osc {
+    // Free-runs `self.lfo_phase` at `lfo_rate` and returns it.
+    fn advance_free_running_lfo(&mut self) -> f32 {
+        let rate = self.params.lfo_rate.value();
+        self.lfo_phase += rate / self.sample_rate;
+        if self.lfo_phase >= 1.0 {
+            self.lfo_phase -= 1.0;
+        }
+        self.lfo_phase
+    }
+
+    // Synced phase comes from the host's song position at the start of the
+    // block, advanced by `sample_offset` samples at `transport.tempo`, so it
+    // stays locked to the grid across seeks/stop-start; falls back to
+    // free-running if the host (or standalone) doesn't report a position.
+    fn next_lfo_value(&mut self, transport: &Transport, sample_offset: usize) -> f32 {
+        let sync = self.params.lfo_sync.value();
+
+        let phase = if sync {
+            match transport.pos_beats() {
+                Some(pos_beats) => {
+                    let offset_beats = transport
+                        .tempo
+                        .map(|tempo| sample_offset as f64 * tempo / 60.0 / self.sample_rate as f64)
+                        .unwrap_or(0.0);
+                    let division_beats = self.params.lfo_division.value().beats();
+                    ((pos_beats + offset_beats) / division_beats).rem_euclid(1.0) as f32
+                }
+                None => self.advance_free_running_lfo(),
+            }
+        } else {
+            self.advance_free_running_lfo()
+        };
+
+        (phase * std::f32::consts::TAU).sin()
+    }
+
+    // Uses the first idle voice, or steals the quietest one if all are busy.
+    fn trigger_voice(&mut self, note: u8, velocity: f32, attack_secs: f32) {
+        let sample_rate = self.sample_rate;
+        let voice = match self.voices.iter_mut().find(|v| v.is_idle()) {
+            Some(voice) => voice,
+            None => self
+                .voices
+                .iter_mut()
+                .min_by(|a, b| a.envelope.level.partial_cmp(&b.envelope.level).unwrap())
+                .expect("NUM_VOICES is never 0"),
+        };
+
+        voice.note_on(note, velocity, sample_rate, attack_secs);
+    }
 }
 
 impl Plugin for Osc {
@@ -104,21 +563,29 @@ impl Plugin for Osc {
 
     fn initialize(
         &mut self,
-        _bus_config: &BusConfig,
+        bus_config: &BusConfig,
         buffer_config: &BufferConfig,
         _context: &mut impl InitContext<Self>,
     ) -> bool {
         self.sample_rate = buffer_config.sample_rate;
+
+        let max_delay_samples = (MAX_DELAY_SECS * self.sample_rate) as usize + 1;
+        let num_channels = bus_config.num_output_channels.max(1) as usize;
+        self.delay_buffers = vec![vec![0.0; max_delay_samples]; num_channels];
+        self.delay_write_index = 0;
+
         true
     }
 
     fn reset(&mut self) {
         // TODO (kas) This is... terribly inefficient to say the least.
         let tmp = Self::default();
-        self.phase = tmp.phase;
-        self.midi_note_freq = tmp.midi_note_freq;
-        self.midi_note_gain = tmp.midi_note_gain;
-        self.midi_note_id = tmp.midi_note_id;
+        self.voices = tmp.voices;
+        self.lfo_phase = tmp.lfo_phase;
+        for buf in self.delay_buffers.iter_mut() {
+            buf.iter_mut().for_each(|sample| *sample = 0.0);
+        }
+        self.delay_write_index = 0;
     }
 
     fn process(
@@ -129,8 +596,11 @@ impl Plugin for Osc {
     ) -> ProcessStatus {
         for (sid, ch_samples) in buffer.iter_samples().enumerate() {
             let gain = self.params.gain.smoothed.next(); // .next() ?!
+            let pd = self.params.pd.smoothed.next();
+            let lfo_depth = self.params.lfo_depth.smoothed.next();
+            let lfo_value = self.next_lfo_value(context.transport(), sid);
 
-            let sine = {
+            let mix = {
                 while let Some(event) = context.next_event() {
                     if event.timing() > sid as u32 {
                         break;
@@ -138,30 +608,79 @@ impl Plugin for Osc {
 
                     match event {
                         NoteEvent::NoteOn { note, velocity, .. } => {
-                            self.midi_note_id = note;
-                            self.midi_note_freq = util::midi_note_to_freq(note);
-                            self.midi_note_gain.set_target(self.sample_rate, velocity);
+                            let attack_secs = self.params.attack.value() / 1000.0;
+                            self.trigger_voice(note, velocity, attack_secs);
                         }
-                        NoteEvent::NoteOff { note, .. } if note == self.midi_note_id => {
-                            self.midi_note_gain.set_target(self.sample_rate, 0.0)
+                        NoteEvent::NoteOff { note, .. } => {
+                            let release_secs = self.params.release.value() / 1000.0;
+                            for voice in self
+                                .voices
+                                .iter_mut()
+                                .filter(|v| v.midi_note_id == note && !v.is_idle())
+                            {
+                                voice.envelope.note_off(self.sample_rate, release_secs);
+                            }
                         }
-                        NoteEvent::PolyPressure { note, pressure, .. }
-                            if note == self.midi_note_id =>
-                        {
-                            self.midi_note_gain.set_target(self.sample_rate, pressure);
+                        NoteEvent::PolyPressure { note, pressure, .. } => {
+                            for voice in self
+                                .voices
+                                .iter_mut()
+                                .filter(|v| v.midi_note_id == note && !v.is_idle())
+                            {
+                                voice.envelope.peak = pressure;
+                            }
                         }
                         _ => (),
                     }
                 }
 
-                self.calculate_sine(self.midi_note_freq) * self.midi_note_gain.next()
+                let decay_secs = self.params.decay.value() / 1000.0;
+                let sustain = self.params.sustain.value();
+                let mode = self.params.mode.value();
+                let sample_rate = self.sample_rate;
+                let lfo_destination = self.params.lfo_destination.value();
+
+                let (pitch_mod, amp_mod) = match lfo_destination {
+                    LfoDestination::Pitch => (2.0f32.powf(lfo_value * lfo_depth / 12.0), 1.0),
+                    LfoDestination::Amplitude => {
+                        (1.0, 1.0 - lfo_depth * (1.0 - lfo_value) / 2.0)
+                    }
+                };
+
+                self.voices
+                    .iter_mut()
+                    .filter(|v| !v.is_idle())
+                    .map(|voice| {
+                        let envelope_level = voice.envelope.next(sample_rate, decay_secs, sustain);
+                        let frequency = voice.midi_note_freq * pitch_mod;
+                        voice.calculate_sample(mode, sample_rate, frequency, pd)
+                            * envelope_level
+                            * amp_mod
+                    })
+                    .sum::<f32>()
             };
 
-            for sample in ch_samples {
+            let delay_time = self.params.delay_time.value();
+            let delay_feedback = self.params.delay_feedback.smoothed.next();
+            let delay_mix = self.params.delay_mix.smoothed.next();
+
+            for (channel, sample) in ch_samples.into_iter().enumerate() {
                 // TODO (kas) using this gain adds a 1-sample delay to gain adjustment.
                 // Why?
-                *sample = sine * util::db_to_gain_fast(gain);
+                let dry = mix * util::db_to_gain_fast(gain);
+
+                let buf = &mut self.delay_buffers[channel];
+                let buf_len = buf.len();
+                let delay_samples =
+                    ((delay_time * self.sample_rate) as usize).clamp(1, buf_len - 1);
+                let read_index = (self.delay_write_index + buf_len - delay_samples) % buf_len;
+                let delayed = buf[read_index];
+
+                buf[self.delay_write_index] = dry + delayed * delay_feedback;
+                *sample = dry + delayed * delay_mix;
             }
+
+            self.delay_write_index = (self.delay_write_index + 1) % self.delay_buffers[0].len();
         }
         ProcessStatus::KeepAlive
     }